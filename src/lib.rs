@@ -0,0 +1,22 @@
+use pyo3::prelude::*;
+
+use crate::ratelimit::errors::RateLimiterException;
+use crate::semaphore::errors::{
+    MaxPositionExceededError, SemaphoreException, SemaphoreTimeoutError,
+};
+
+pub(crate) mod ratelimit;
+pub(crate) mod semaphore;
+pub(crate) mod utils;
+
+#[pymodule]
+fn traffic_lights(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<semaphore::Semaphore>()?;
+    m.add_class::<semaphore::permit::Permit>()?;
+    m.add_class::<ratelimit::RateLimiter>()?;
+    m.add("MaxPositionExceededError", _py.get_type::<MaxPositionExceededError>())?;
+    m.add("SemaphoreTimeoutError", _py.get_type::<SemaphoreTimeoutError>())?;
+    m.add("SemaphoreException", _py.get_type::<SemaphoreException>())?;
+    m.add("RateLimiterException", _py.get_type::<RateLimiterException>())?;
+    Ok(())
+}
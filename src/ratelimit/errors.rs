@@ -0,0 +1,17 @@
+use pyo3::exceptions::PyException;
+use pyo3::{create_exception, PyErr};
+use thiserror::Error;
+
+create_exception!(traffic_lights, RateLimiterException, PyException);
+
+#[derive(Error, Debug)]
+pub(crate) enum RateLimiterError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+}
+
+impl From<RateLimiterError> for PyErr {
+    fn from(e: RateLimiterError) -> Self {
+        RateLimiterException::new_err(e.to_string())
+    }
+}
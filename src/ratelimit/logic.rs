@@ -0,0 +1,115 @@
+extern crate redis;
+
+use log::debug;
+use once_cell::sync::Lazy;
+use pyo3::PyErr;
+use redis::{Client, Script};
+
+use crate::ratelimit::errors::RateLimiterError;
+use crate::ratelimit::ThreadState;
+use crate::utils::open_client_connection;
+
+/// How long the bucket's bookkeeping hash survives without being
+/// touched, so an abandoned limiter's key doesn't linger forever.
+const KEY_TTL: usize = 300;
+
+/// Refill-and-test, atomically: compute elapsed time since
+/// `last_refill`, add `elapsed * rate` tokens capped at `burst`, and
+/// either take one (returning 0) or report how many milliseconds until
+/// one is available. `rate`/`burst` are read from the hash itself when
+/// present, so a runtime update (see `update_limits`) is picked up by
+/// every subsequent script invocation without a restart.
+static ACQUIRE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local bucket_key = KEYS[1]
+        local now = tonumber(ARGV[1])
+        local default_rate = tonumber(ARGV[2])
+        local default_burst = tonumber(ARGV[3])
+        local ttl = ARGV[4]
+
+        local bucket = redis.call('HMGET', bucket_key, 'tokens', 'last_refill', 'rate', 'burst')
+        local rate = tonumber(bucket[3]) or default_rate
+        local burst = tonumber(bucket[4]) or default_burst
+        local tokens = tonumber(bucket[1])
+        local last_refill = tonumber(bucket[2])
+
+        if tokens == nil or last_refill == nil then
+            tokens = burst
+            last_refill = now
+        end
+
+        local elapsed = math.max(now - last_refill, 0)
+        tokens = math.min(burst, tokens + elapsed * rate)
+
+        local wait_ms = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+        else
+            wait_ms = math.ceil(((1 - tokens) / rate) * 1000)
+        end
+
+        redis.call('HSET', bucket_key, 'tokens', tokens, 'last_refill', now, 'rate', rate, 'burst', burst)
+        redis.call('EXPIRE', bucket_key, ttl)
+        return wait_ms
+        ",
+    )
+});
+
+/// Take one token from the bucket, waiting out any refill delay the
+/// script reports. Returns once a token has actually been consumed.
+pub(crate) async fn acquire(ts: ThreadState) -> Result<(), PyErr> {
+    let mut connection = open_client_connection::<Client, RateLimiterError>(&ts.client).await?;
+
+    loop {
+        let now = now_unix_secs(&mut connection).await.map_err(PyErr::from)?;
+
+        let wait_ms: i64 = ACQUIRE_SCRIPT
+            .key(&ts.bucket_key)
+            .arg(now)
+            .arg(ts.rate)
+            .arg(ts.burst)
+            .arg(KEY_TTL)
+            .invoke_async(&mut connection)
+            .await
+            .map_err(|e| PyErr::from(RateLimiterError::from(e)))?;
+
+        if wait_ms <= 0 {
+            return Ok(());
+        }
+
+        debug!("Bucket {} empty, sleeping {}ms", ts.bucket_key, wait_ms);
+        tokio::time::sleep(std::time::Duration::from_millis(wait_ms as u64)).await;
+    }
+}
+
+/// Overwrite the bucket's stored `rate`/`burst` so every client sharing
+/// this key picks up the new limits on its next acquire, without
+/// restarting. Mirrors reacting to a server-advertised rate limit (e.g.
+/// a `429` response) live.
+pub(crate) async fn update_limits(ts: ThreadState, rate: f64, burst: f64) -> Result<(), PyErr> {
+    let mut connection = open_client_connection::<Client, RateLimiterError>(&ts.client).await?;
+    redis::cmd("HSET")
+        .arg(&ts.bucket_key)
+        .arg("rate")
+        .arg(rate)
+        .arg("burst")
+        .arg(burst)
+        .query_async::<_, ()>(&mut connection)
+        .await
+        .map_err(|e| PyErr::from(RateLimiterError::from(e)))?;
+    redis::cmd("EXPIRE")
+        .arg(&ts.bucket_key)
+        .arg(KEY_TTL)
+        .query_async::<_, ()>(&mut connection)
+        .await
+        .map_err(|e| PyErr::from(RateLimiterError::from(e)))?;
+    Ok(())
+}
+
+async fn now_unix_secs(
+    connection: &mut redis::aio::MultiplexedConnection,
+) -> Result<f64, RateLimiterError> {
+    let (secs, micros): (i64, i64) = redis::cmd("TIME").query_async(connection).await?;
+    Ok(secs as f64 + micros as f64 / 1_000_000.0)
+}
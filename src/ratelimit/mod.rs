@@ -0,0 +1,76 @@
+use pyo3::prelude::*;
+use redis::Client;
+
+pub(crate) mod errors;
+pub(crate) mod logic;
+
+use crate::ratelimit::logic::{acquire, update_limits};
+
+/// Sibling of [`crate::semaphore::ThreadState`]: per-call state needed
+/// to talk to Redis about one rate limiter, threaded through the async
+/// logic the same way the semaphore does.
+#[derive(Clone)]
+pub(crate) struct ThreadState {
+    pub client: Client,
+    pub bucket_key: String,
+    /// Used as the bucket's initial/default rate and burst the first
+    /// time it's touched; after that, the values stored in Redis win
+    /// (see `update_limits`).
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// A Redis-backed token bucket: bounds *throughput* (tokens/second with
+/// a burst allowance) rather than *concurrency*, the way
+/// [`crate::semaphore::Semaphore`] does.
+#[pyclass]
+pub struct RateLimiter {
+    bucket_key: String,
+    redis_url: String,
+    rate: f64,
+    burst: f64,
+}
+
+#[pymethods]
+impl RateLimiter {
+    #[new]
+    #[pyo3(signature = (rate, burst, redis_url, bucket_key))]
+    fn new(rate: f64, burst: f64, redis_url: String, bucket_key: String) -> PyResult<Self> {
+        Ok(Self {
+            bucket_key,
+            redis_url,
+            rate,
+            burst,
+        })
+    }
+
+    /// Block until a token is available, then consume it.
+    fn acquire(&self, py: Python) -> PyResult<()> {
+        let ts = self.thread_state();
+        py.allow_threads(|| {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(acquire(ts))
+        })
+    }
+
+    /// Update the shared rate/burst for every client using this bucket
+    /// key, effective immediately, without restarting any of them.
+    fn update_limits(&self, py: Python, rate: f64, burst: f64) -> PyResult<()> {
+        let ts = self.thread_state();
+        py.allow_threads(|| {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(update_limits(ts, rate, burst))
+        })
+    }
+}
+
+impl RateLimiter {
+    fn thread_state(&self) -> ThreadState {
+        ThreadState {
+            client: Client::open(self.redis_url.as_str()).expect("invalid redis url"),
+            bucket_key: self.bucket_key.clone(),
+            rate: self.rate,
+            burst: self.burst,
+        }
+    }
+}
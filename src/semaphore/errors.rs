@@ -0,0 +1,48 @@
+use std::sync::mpsc::{RecvError, SendError};
+
+use pyo3::exceptions::PyException;
+use pyo3::{create_exception, PyErr};
+use thiserror::Error;
+use tokio::task::JoinError;
+
+create_exception!(traffic_lights, MaxPositionExceededError, PyException);
+create_exception!(traffic_lights, SemaphoreTimeoutError, PyException);
+create_exception!(traffic_lights, SemaphoreException, PyException);
+
+#[derive(Error, Debug)]
+pub(crate) enum SemaphoreError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error("{0}")]
+    MaxPositionExceeded(String),
+
+    #[error("Timed out waiting for a slot")]
+    Timeout,
+
+    #[error("Failed to send data between threads: {0}")]
+    Send(String),
+
+    #[error("Failed to receive data between threads: {0}")]
+    Recv(#[from] RecvError),
+
+    #[error("Background task panicked: {0}")]
+    Join(#[from] JoinError),
+}
+
+impl<T> From<SendError<T>> for SemaphoreError {
+    fn from(e: SendError<T>) -> Self {
+        SemaphoreError::Send(e.to_string())
+    }
+}
+
+impl From<SemaphoreError> for PyErr {
+    fn from(e: SemaphoreError) -> Self {
+        let message = e.to_string();
+        match e {
+            SemaphoreError::MaxPositionExceeded(msg) => MaxPositionExceededError::new_err(msg),
+            SemaphoreError::Timeout => SemaphoreTimeoutError::new_err(message),
+            other => SemaphoreException::new_err(other.to_string()),
+        }
+    }
+}
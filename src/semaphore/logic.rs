@@ -2,6 +2,7 @@ extern crate redis;
 
 use std::num::NonZeroUsize;
 use std::sync::mpsc::channel;
+use std::time::Duration;
 
 use log::debug;
 use pyo3::PyErr;
@@ -61,6 +62,55 @@ pub(crate) async fn wait_for_slot(ts: ThreadState) -> Result<(), PyErr> {
     Ok(())
 }
 
+/// Try to claim a slot without waiting. Returns `true` if a slot was
+/// claimed, `false` if the semaphore was already full. Never leaves our
+/// id in the queue on failure, unlike [`wait_for_slot`], which is
+/// willing to wait in line.
+pub(crate) async fn try_acquire_slot(ts: ThreadState) -> Result<bool, PyErr> {
+    let mut connection = open_client_connection::<Client, SemaphoreError>(&ts.client).await?;
+
+    let position: u32 = connection
+        .rpush(&ts.queue_key, &ts.id)
+        .await
+        .map_err(|e| PyErr::from(SemaphoreError::from(e)))?;
+
+    // `rpush` returns the queue length, i.e. our 1-based position
+    if position <= ts.capacity {
+        debug!("Position is within capacity. Claimed slot.");
+        return Ok(true);
+    }
+
+    debug!("Position is greater than capacity. Rolling back.");
+    connection
+        .lrem::<_, _, ()>(&ts.queue_key, 1, &ts.id)
+        .await
+        .map_err(|e| PyErr::from(SemaphoreError::from(e)))?;
+    Ok(false)
+}
+
+/// Like [`wait_for_slot`], but gives up after `timeout` instead of
+/// waiting indefinitely, returning [`SemaphoreError::Timeout`]. An
+/// abandoned wait removes its id from the queue with `lrem`, so it
+/// doesn't keep occupying (and blocking) a position behind it.
+pub(crate) async fn wait_for_slot_timeout(ts: ThreadState, timeout: Duration) -> Result<(), PyErr> {
+    let id = ts.id.clone();
+    let queue_key = ts.queue_key.clone();
+    let client = ts.client.clone();
+
+    match tokio::time::timeout(timeout, wait_for_slot(ts)).await {
+        Ok(result) => result,
+        Err(_) => {
+            let mut connection =
+                open_client_connection::<Client, SemaphoreError>(&client).await?;
+            connection
+                .lrem::<_, _, ()>(&queue_key, 1, &id)
+                .await
+                .map_err(|e| PyErr::from(SemaphoreError::from(e)))?;
+            Err(PyErr::from(SemaphoreError::Timeout))
+        }
+    }
+}
+
 /// Pop from the queue, to add capacity back to the
 /// semaphore, and refresh expiry for the queue.
 pub(crate) async fn clean_up(ts: ThreadState) -> SemResult<()> {
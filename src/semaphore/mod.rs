@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use redis::Client;
+
+pub(crate) mod errors;
+pub(crate) mod logic;
+pub(crate) mod permit;
+pub(crate) mod token;
+pub(crate) mod utils;
+
+use crate::semaphore::logic::{try_acquire_slot, wait_for_slot, wait_for_slot_timeout};
+use crate::semaphore::permit::Permit;
+use crate::semaphore::token::{acquire_many, acquire_many_timeout, try_acquire_many};
+
+/// Which Redis data structure backs the semaphore's waiting line.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    /// The original design: a list of waiter ids, polled with `lpos`.
+    Queue,
+    /// A list of `capacity` opaque tokens, acquired with a blocking
+    /// `BLPOP` instead of polling.
+    TokenList,
+}
+
+#[derive(Clone)]
+pub(crate) struct ThreadState {
+    pub client: Client,
+    pub id: String,
+    pub capacity: u32,
+    pub max_position: u32,
+    pub sleep_duration: Duration,
+    pub backend: Backend,
+
+    /// `Backend::Queue` key: list of waiter ids.
+    pub queue_key: String,
+
+    /// `Backend::TokenList` keys.
+    pub tokens_key: String,
+    pub initialized_key: String,
+    pub leases_key: String,
+    pub lease_duration: Duration,
+
+    /// How many tokens a single acquire consumes. Only meaningful for
+    /// `Backend::TokenList`: the queue backend's position model has no
+    /// sensible way to represent a waiter that isn't worth exactly one
+    /// slot.
+    pub weight: u32,
+}
+
+#[pyclass]
+pub struct Semaphore {
+    capacity: u32,
+    max_position: u32,
+    sleep_duration: Duration,
+    queue_key: String,
+    redis_url: String,
+    backend: Backend,
+    lease_duration: Duration,
+    weight: u32,
+}
+
+#[pymethods]
+impl Semaphore {
+    #[new]
+    #[pyo3(signature = (
+        capacity,
+        redis_url,
+        queue_key,
+        max_position = 0,
+        sleep_duration = 0.01,
+        blocking = false,
+        lease_duration = 30.0,
+        weight = 1,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        capacity: u32,
+        redis_url: String,
+        queue_key: String,
+        max_position: u32,
+        sleep_duration: f64,
+        blocking: bool,
+        lease_duration: f64,
+        weight: u32,
+    ) -> PyResult<Self> {
+        let backend = if blocking {
+            Backend::TokenList
+        } else {
+            Backend::Queue
+        };
+        if weight > 1 && backend != Backend::TokenList {
+            return Err(PyValueError::new_err(
+                "weight > 1 requires blocking=True (the token-list backend); the queue \
+                 backend's position model can't account for partial slots",
+            ));
+        }
+        if weight > capacity {
+            return Err(PyValueError::new_err(
+                "weight cannot exceed capacity; a weight this large could never be \
+                 satisfied, so acquire() would block forever",
+            ));
+        }
+        Ok(Self {
+            capacity,
+            max_position,
+            sleep_duration: Duration::from_secs_f64(sleep_duration),
+            queue_key,
+            redis_url,
+            backend,
+            lease_duration: Duration::from_secs_f64(lease_duration),
+            weight,
+        })
+    }
+
+    /// Wait for `weight` units of capacity and return a [`Permit`]
+    /// holding them all. Use as a context manager, `with
+    /// semaphore.acquire():`, to release automatically on exit (or on
+    /// drop, if the `with` block is never reached).
+    fn acquire(&self, py: Python) -> PyResult<Permit> {
+        let ts = self.thread_state();
+        let wait_ts = ts.clone();
+        let tokens = py.allow_threads(|| {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async move {
+                match wait_ts.backend {
+                    Backend::Queue => wait_for_slot(wait_ts).await.map(|_| Vec::new()),
+                    Backend::TokenList => acquire_many(wait_ts).await,
+                }
+            })
+        })?;
+        Ok(Permit::new(ts, tokens))
+    }
+
+    /// Try to claim `weight` units of capacity without waiting. Returns
+    /// `None` if the semaphore doesn't currently have that much to give.
+    fn try_acquire(&self, py: Python) -> PyResult<Option<Permit>> {
+        let ts = self.thread_state();
+        let wait_ts = ts.clone();
+        let claimed: Option<Vec<String>> = py.allow_threads(|| {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async move {
+                let claimed = match wait_ts.backend {
+                    Backend::Queue => try_acquire_slot(wait_ts).await?.then_some(Vec::new()),
+                    Backend::TokenList => try_acquire_many(wait_ts).await?,
+                };
+                Ok::<_, PyErr>(claimed)
+            })
+        })?;
+        Ok(claimed.map(|tokens| Permit::new(ts, tokens)))
+    }
+
+    /// Wait for `weight` units of capacity, giving up after `timeout`
+    /// seconds and raising `SemaphoreTimeoutError` instead of waiting
+    /// indefinitely.
+    fn acquire_timeout(&self, py: Python, timeout: f64) -> PyResult<Permit> {
+        let ts = self.thread_state();
+        let wait_ts = ts.clone();
+        let timeout = Duration::from_secs_f64(timeout);
+        let tokens = py.allow_threads(|| {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(async move {
+                match wait_ts.backend {
+                    Backend::Queue => wait_for_slot_timeout(wait_ts, timeout).await.map(|_| Vec::new()),
+                    Backend::TokenList => acquire_many_timeout(wait_ts, timeout).await,
+                }
+            })
+        })?;
+        Ok(Permit::new(ts, tokens))
+    }
+}
+
+impl Semaphore {
+    fn thread_state(&self) -> ThreadState {
+        ThreadState {
+            client: Client::open(self.redis_url.as_str()).expect("invalid redis url"),
+            id: uuid::Uuid::new_v4().to_string(),
+            capacity: self.capacity,
+            max_position: self.max_position,
+            sleep_duration: self.sleep_duration,
+            backend: self.backend,
+            queue_key: self.queue_key.clone(),
+            tokens_key: format!("{}:tokens", self.queue_key),
+            initialized_key: format!("{}:initialized", self.queue_key),
+            leases_key: format!("{}:leases", self.queue_key),
+            lease_duration: self.lease_duration,
+            weight: self.weight,
+        }
+    }
+}
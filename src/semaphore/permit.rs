@@ -0,0 +1,88 @@
+use pyo3::prelude::*;
+
+use crate::semaphore::logic::clean_up;
+use crate::semaphore::token::release_many;
+use crate::semaphore::{Backend, ThreadState};
+
+/// A held slot, worth `weight` units of capacity. Dropping it (or
+/// calling `release()`/exiting a `with` block) returns capacity to the
+/// semaphore. Borrowed from tokio's `Sender::reserve()` permit:
+/// acquiring is only half the story, holding onto *something* that
+/// gives capacity back is what makes leaking a slot hard to do by
+/// accident.
+#[pyclass]
+pub struct Permit {
+    ts: ThreadState,
+    /// Only set for `Backend::TokenList`, which needs the specific
+    /// tokens back to release them. One entry per unit of weight.
+    tokens: Vec<String>,
+    released: bool,
+}
+
+impl Permit {
+    pub(crate) fn new(ts: ThreadState, tokens: Vec<String>) -> Self {
+        Self {
+            ts,
+            tokens,
+            released: false,
+        }
+    }
+
+    /// Shared by `release()`, `__exit__` and `Drop`. Idempotent: a
+    /// second call is a no-op, so an explicit early release followed by
+    /// drop doesn't pop the same tokens off the queue twice.
+    fn release_once(&mut self) -> Option<(ThreadState, Vec<String>)> {
+        if self.released {
+            return None;
+        }
+        self.released = true;
+        Some((self.ts.clone(), std::mem::take(&mut self.tokens)))
+    }
+}
+
+async fn do_release(ts: ThreadState, tokens: Vec<String>) -> PyResult<()> {
+    match ts.backend {
+        Backend::Queue => Ok(clean_up(ts).await?),
+        Backend::TokenList => Ok(release_many(ts, tokens).await?),
+    }
+}
+
+#[pymethods]
+impl Permit {
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        py: Python,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<()> {
+        self.release(py)
+    }
+
+    /// Return this permit's slot(s) to the semaphore. Safe to call more
+    /// than once, and safe to call before the permit is dropped.
+    fn release(&mut self, py: Python) -> PyResult<()> {
+        let Some((ts, tokens)) = self.release_once() else {
+            return Ok(());
+        };
+        py.allow_threads(|| {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(do_release(ts, tokens))
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let Some((ts, tokens)) = self.release_once() else {
+            return;
+        };
+        if let Ok(rt) = tokio::runtime::Runtime::new() {
+            let _ = rt.block_on(do_release(ts, tokens));
+        }
+    }
+}
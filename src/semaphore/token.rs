@@ -0,0 +1,392 @@
+extern crate redis;
+
+use std::time::Duration;
+
+use log::debug;
+use once_cell::sync::Lazy;
+use pyo3::PyErr;
+use redis::{AsyncCommands, Client, Script};
+
+use crate::semaphore::errors::SemaphoreError;
+use crate::semaphore::utils::SemResult;
+use crate::semaphore::ThreadState;
+use crate::utils::open_client_connection;
+
+/// Expiry refreshed on `tokens_key`/`initialized_key` on every
+/// acquire/release, so an idle semaphore's bookkeeping keys still
+/// eventually disappear from Redis.
+const KEY_TTL: i64 = 30;
+
+/// Primes `tokens_key` with exactly `capacity` token elements the first
+/// time any client touches this semaphore, guarded by `SET NX` on
+/// `initialized_key` so a thundering herd of callers only primes once.
+static INIT_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local initialized_key = KEYS[1]
+        local tokens_key = KEYS[2]
+        local capacity = tonumber(ARGV[1])
+        local ttl = ARGV[2]
+
+        if redis.call('SET', initialized_key, '1', 'NX', 'EX', ttl) then
+            for i = 1, capacity do
+                redis.call('RPUSH', tokens_key, 'token:' .. i)
+            end
+            redis.call('EXPIRE', tokens_key, ttl)
+        end
+        return 1
+        ",
+    )
+});
+
+/// Returns any token whose lease has outlived `lease_duration` (its holder
+/// crashed without releasing) to the token list, atomically.
+static REAP_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local leases_key = KEYS[1]
+        local tokens_key = KEYS[2]
+        local cutoff = ARGV[1]
+
+        local expired = redis.call('ZRANGEBYSCORE', leases_key, '-inf', cutoff)
+        for _, token in ipairs(expired) do
+            redis.call('ZREM', leases_key, token)
+            redis.call('RPUSH', tokens_key, token)
+        end
+        return #expired
+        ",
+    )
+});
+
+/// Drops each token's lease and, only if that lease was still live,
+/// pushes the token back onto the list — atomically per token, so a
+/// crash (or a dropped future) can never land between the `ZREM` and
+/// the `RPUSH` and strand a token that the reaper can no longer reclaim
+/// (its lease is already gone). Also refreshes every bookkeeping key's
+/// TTL in the same round trip.
+static RELEASE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        local leases_key = KEYS[1]
+        local tokens_key = KEYS[2]
+        local initialized_key = KEYS[3]
+        local ttl = table.remove(ARGV)
+
+        for _, token in ipairs(ARGV) do
+            if redis.call('ZREM', leases_key, token) == 1 then
+                redis.call('RPUSH', tokens_key, token)
+            end
+        end
+        redis.call('EXPIRE', tokens_key, ttl)
+        redis.call('EXPIRE', initialized_key, ttl)
+        redis.call('EXPIRE', leases_key, ttl)
+        return 1
+        ",
+    )
+});
+
+/// Block until `ts.weight` tokens are available and claim them all.
+/// Unlike [`crate::semaphore::logic::wait_for_slot`], this never polls:
+/// each token is obtained with a `BLPOP`, which wakes the instant a
+/// token is pushed back by a releasing holder.
+///
+/// Tokens are collected one `BLPOP` at a time into a staging vec, so
+/// two large acquirers each grabbing part of what they need can't
+/// deadlock one another. Every token is leased (see [`record_lease`])
+/// right after its `BLPOP` returns, so if this call is interrupted
+/// (timeout or error) before it finishes, the partial collection is
+/// rolled back explicitly below, and if the process crashes, every
+/// token already leased is reclaimed later by the same reaper that
+/// returns any other orphaned token. A token whose `BLPOP` returned but
+/// whose lease was not yet recorded when the process crashed is not
+/// reclaimed by the reaper.
+pub(crate) async fn acquire_many(ts: ThreadState) -> Result<Vec<String>, PyErr> {
+    acquire_many_before(ts, None).await
+}
+
+/// Like [`acquire_many`], but gives up once `deadline` passes instead of
+/// blocking indefinitely, rolling back whatever was collected so far.
+///
+/// The deadline is enforced inside the `BLPOP` loop itself (each
+/// iteration's `BLPOP` is given only the time remaining) rather than by
+/// wrapping the whole call in an outer `tokio::time::timeout`: dropping
+/// a future mid-`BLPOP` on an outer timeout would skip the rollback
+/// below entirely, stranding any tokens already claimed.
+async fn acquire_many_before(
+    ts: ThreadState,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<Vec<String>, PyErr> {
+    let weight = ts.weight.max(1) as usize;
+    let mut connection = open_client_connection::<Client, SemaphoreError>(&ts.client).await?;
+    prepare(&mut connection, &ts)
+        .await
+        .map_err(PyErr::from)?;
+
+    let mut collected: Vec<String> = Vec::with_capacity(weight);
+    while collected.len() < weight {
+        let blpop_timeout = match deadline {
+            None => 0.0,
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+                if remaining.is_zero() {
+                    let _ = release_many(ts.clone(), std::mem::take(&mut collected)).await;
+                    return Err(PyErr::from(SemaphoreError::Timeout));
+                }
+                // Redis reads a BLPOP timeout under 1ms as 0, i.e. "block
+                // forever", so never pass less than that.
+                remaining.as_secs_f64().max(0.001)
+            }
+        };
+        debug!("Waiting on BLPOP {} ({}/{})", ts.tokens_key, collected.len(), weight);
+        let blpop: Result<Option<(String, String)>, _> =
+            connection.blpop(&ts.tokens_key, blpop_timeout).await;
+        let token = match blpop.map_err(|e| PyErr::from(SemaphoreError::from(e))) {
+            Ok(Some((_, token))) => token,
+            Ok(None) => {
+                let _ = release_many(ts.clone(), std::mem::take(&mut collected)).await;
+                return Err(PyErr::from(SemaphoreError::Timeout));
+            }
+            Err(e) => {
+                let _ = release_many(ts.clone(), std::mem::take(&mut collected)).await;
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = record_lease(&mut connection, &ts, &token)
+            .await
+            .map_err(PyErr::from)
+        {
+            // This token's lease may not have been written, and
+            // `release_many` only pushes back tokens whose lease it
+            // removes, so hand this one back directly.
+            let _ = connection.zrem::<_, _, ()>(&ts.leases_key, &token).await;
+            let _ = connection.rpush::<_, _, ()>(&ts.tokens_key, &token).await;
+            let _ = release_many(ts.clone(), std::mem::take(&mut collected)).await;
+            return Err(e);
+        }
+        collected.push(token);
+    }
+    debug!("Acquired {} tokens", collected.len());
+    Ok(collected)
+}
+
+/// Try to claim `ts.weight` tokens without blocking. Returns `None` if
+/// the pool runs out before the full weight is claimed, rolling back
+/// whatever was popped so a failed try_acquire doesn't strand capacity.
+pub(crate) async fn try_acquire_many(ts: ThreadState) -> Result<Option<Vec<String>>, PyErr> {
+    let weight = ts.weight.max(1) as usize;
+    let mut connection = open_client_connection::<Client, SemaphoreError>(&ts.client).await?;
+    prepare(&mut connection, &ts)
+        .await
+        .map_err(PyErr::from)?;
+
+    let mut collected: Vec<String> = Vec::with_capacity(weight);
+    while collected.len() < weight {
+        let token: Option<String> = connection
+            .lpop(&ts.tokens_key, None)
+            .await
+            .map_err(|e| PyErr::from(SemaphoreError::from(e)))?;
+        let Some(token) = token else {
+            let _ = release_many(ts.clone(), std::mem::take(&mut collected)).await;
+            return Ok(None);
+        };
+        record_lease(&mut connection, &ts, &token)
+            .await
+            .map_err(PyErr::from)?;
+        collected.push(token);
+    }
+    Ok(Some(collected))
+}
+
+/// Like [`acquire_many`], but gives up after `timeout` instead of
+/// blocking indefinitely, returning [`SemaphoreError::Timeout`]. Any
+/// tokens already collected at that point are rolled back before the
+/// error reaches us (see [`acquire_many_before`]).
+pub(crate) async fn acquire_many_timeout(
+    ts: ThreadState,
+    timeout: Duration,
+) -> Result<Vec<String>, PyErr> {
+    acquire_many_before(ts, Some(tokio::time::Instant::now() + timeout)).await
+}
+
+/// Prime the token list (first caller only) and reap any leases whose
+/// holder crashed, ahead of every acquire attempt.
+async fn prepare(
+    connection: &mut redis::aio::MultiplexedConnection,
+    ts: &ThreadState,
+) -> SemResult<()> {
+    INIT_SCRIPT
+        .key(&ts.initialized_key)
+        .key(&ts.tokens_key)
+        .arg(ts.capacity)
+        .arg(KEY_TTL)
+        .invoke_async::<_, ()>(connection)
+        .await?;
+    reap_expired_leases(connection, ts).await
+}
+
+/// Record an outstanding lease for `token` and refresh key TTLs.
+async fn record_lease(
+    connection: &mut redis::aio::MultiplexedConnection,
+    ts: &ThreadState,
+    token: &str,
+) -> SemResult<()> {
+    let now = now_unix_secs(connection).await?;
+    connection.zadd::<_, _, _, ()>(&ts.leases_key, token, now).await?;
+    connection.expire::<_, ()>(&ts.tokens_key, KEY_TTL).await?;
+    connection.expire::<_, ()>(&ts.initialized_key, KEY_TTL).await?;
+    connection.expire::<_, ()>(&ts.leases_key, KEY_TTL).await?;
+    Ok(())
+}
+
+/// Return every token in `tokens` to the pool and drop their leases —
+/// the release half of `acquire_many`, and also how a stalled weighted
+/// acquire hands back whatever it managed to collect.
+///
+/// A token is only pushed back if its lease was still live: if the
+/// reaper already reclaimed it (the holder sat past `lease_duration`
+/// before calling this), the lease's `zrem` is a no-op and we must not
+/// `rpush` a second time, or the pool gains a phantom extra token. The
+/// check-and-push happens atomically in [`RELEASE_SCRIPT`] so a crash
+/// between the two can't strand a token outside the reaper's reach.
+pub(crate) async fn release_many(ts: ThreadState, tokens: Vec<String>) -> SemResult<()> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    let mut connection = open_client_connection::<Client, SemaphoreError>(&ts.client).await?;
+    RELEASE_SCRIPT
+        .key(&ts.leases_key)
+        .key(&ts.tokens_key)
+        .key(&ts.initialized_key)
+        .arg(&tokens)
+        .arg(KEY_TTL)
+        .invoke_async::<_, ()>(&mut connection)
+        .await?;
+    Ok(())
+}
+
+async fn reap_expired_leases(
+    connection: &mut redis::aio::MultiplexedConnection,
+    ts: &ThreadState,
+) -> SemResult<()> {
+    let cutoff = now_unix_secs(connection).await? - ts.lease_duration.as_secs_f64();
+    REAP_SCRIPT
+        .key(&ts.leases_key)
+        .key(&ts.tokens_key)
+        .arg(cutoff)
+        .invoke_async::<_, ()>(connection)
+        .await?;
+    Ok(())
+}
+
+async fn now_unix_secs(connection: &mut redis::aio::MultiplexedConnection) -> SemResult<f64> {
+    let (secs, micros): (i64, i64) = redis::cmd("TIME").query_async(connection).await?;
+    Ok(secs as f64 + micros as f64 / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use redis::AsyncCommands;
+
+    use super::{acquire_many, acquire_many_timeout, release_many};
+    use crate::semaphore::errors::SemaphoreError;
+    use crate::semaphore::{Backend, ThreadState};
+
+    /// These hit a real Redis instance and are skipped unless `REDIS_URL`
+    /// is set, the same convention redis-rs itself uses for its own
+    /// integration tests.
+    fn test_thread_state(suffix: &str, lease_duration: Duration) -> Option<ThreadState> {
+        test_thread_state_weighted(suffix, lease_duration, 1, 1)
+    }
+
+    fn test_thread_state_weighted(
+        suffix: &str,
+        lease_duration: Duration,
+        capacity: u32,
+        weight: u32,
+    ) -> Option<ThreadState> {
+        let redis_url = std::env::var("REDIS_URL").ok()?;
+        let queue_key = format!("traffic-lights-test:{suffix}:{}", uuid::Uuid::new_v4());
+        Some(ThreadState {
+            client: redis::Client::open(redis_url).expect("invalid REDIS_URL"),
+            id: uuid::Uuid::new_v4().to_string(),
+            capacity,
+            max_position: 0,
+            sleep_duration: Duration::from_millis(10),
+            backend: Backend::TokenList,
+            queue_key: queue_key.clone(),
+            tokens_key: format!("{queue_key}:tokens"),
+            initialized_key: format!("{queue_key}:initialized"),
+            leases_key: format!("{queue_key}:leases"),
+            lease_duration,
+            weight,
+        })
+    }
+
+    /// A lease that the reaper already reclaimed (because its holder sat
+    /// past `lease_duration`) must not be pushed back a second time when
+    /// the original holder eventually calls `release_many` — that would
+    /// leave two copies of the same token in the pool.
+    #[tokio::test]
+    async fn release_after_reap_does_not_duplicate_the_token() {
+        let Some(ts) = test_thread_state("reap-dup", Duration::from_millis(50)) else {
+            return;
+        };
+
+        let held = acquire_many(ts.clone()).await.expect("initial acquire");
+
+        // Let the lease age past lease_duration, then trigger reaping by
+        // acquiring again — the reaper runs ahead of every acquire.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reclaimed = acquire_many(ts.clone())
+            .await
+            .expect("acquire after reap should see the reclaimed token");
+
+        // The original holder releases the lease it still (mistakenly)
+        // believes it owns.
+        release_many(ts.clone(), held).await.expect("stale release");
+
+        // Only the reclaimed token should be in the pool — not a second,
+        // duplicated copy of the same token string.
+        release_many(ts.clone(), reclaimed).await.expect("release");
+        let mut connection = ts
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .expect("connection");
+        let pool_len: usize = connection.llen(&ts.tokens_key).await.expect("llen");
+        assert_eq!(pool_len, ts.capacity as usize);
+    }
+
+    /// A weighted acquire that times out partway through must return
+    /// every token it already collected, rather than stranding them —
+    /// the deadline is enforced inside the BLPOP loop precisely so this
+    /// rollback runs instead of being skipped by a dropped future.
+    #[tokio::test]
+    async fn weighted_acquire_timeout_rolls_back_partial_collection() {
+        // Only one of the two tokens this acquire needs will ever be
+        // available, so it's guaranteed to time out having collected one.
+        let Some(ts) = test_thread_state_weighted("weighted-timeout", Duration::from_secs(30), 1, 2)
+        else {
+            return;
+        };
+
+        let result = acquire_many_timeout(ts.clone(), Duration::from_millis(200)).await;
+        assert!(matches!(
+            result,
+            Err(e) if e.to_string().contains(&SemaphoreError::Timeout.to_string())
+        ));
+
+        let mut connection = ts
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .expect("connection");
+        let pool_len: usize = connection.llen(&ts.tokens_key).await.expect("llen");
+        assert_eq!(pool_len, ts.capacity as usize);
+        let leases_len: usize = connection.zcard(&ts.leases_key).await.expect("zcard");
+        assert_eq!(leases_len, 0);
+    }
+}
@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+use crate::semaphore::errors::SemaphoreError;
+
+pub(crate) type SemResult<T> = Result<T, SemaphoreError>;
+
+/// Scale the poll interval with how far back in the queue we are, so a
+/// waiter far from the front doesn't hammer Redis every `sleep_duration`.
+pub(crate) fn estimate_appropriate_sleep_duration(
+    position: &u32,
+    capacity: &u32,
+    sleep_duration: &Duration,
+) -> Duration {
+    let distance = position.saturating_sub(*capacity).max(1);
+    *sleep_duration * distance
+}
@@ -0,0 +1,32 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use redis::aio::MultiplexedConnection;
+use redis::{Client, RedisResult};
+
+/// Anything that can hand out a multiplexed async connection, so that
+/// `open_client_connection` can be shared by every subsystem (semaphore,
+/// rate limiter, ...) regardless of which error type it reports through.
+pub(crate) trait GetMultiplexedConnection {
+    fn get_multiplexed_connection(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = RedisResult<MultiplexedConnection>> + Send + '_>>;
+}
+
+impl GetMultiplexedConnection for Client {
+    fn get_multiplexed_connection(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = RedisResult<MultiplexedConnection>> + Send + '_>> {
+        Box::pin(self.get_multiplexed_async_connection())
+    }
+}
+
+/// Open a connection against `client`, converting the underlying Redis
+/// error into whichever error type the calling subsystem uses.
+pub(crate) async fn open_client_connection<C, E>(client: &C) -> Result<MultiplexedConnection, E>
+where
+    C: GetMultiplexedConnection,
+    E: From<redis::RedisError>,
+{
+    client.get_multiplexed_connection().await.map_err(E::from)
+}